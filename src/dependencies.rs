@@ -1,15 +1,78 @@
-use std::collections::{BTreeMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
-use cargo_toml::{Dependency, DependencyDetail};
-use semver::VersionReq;
+use cargo_toml::{Dependency, DependencyDetail, DepsSet, Manifest, Target};
+use semver::{Comparator, Op, Version, VersionReq};
+use thiserror::Error;
+
+/// The three places cargo lets a package declare a dependency, mirroring cargo's
+/// own `DepKind`. There's only one `[workspace.dependencies]` table for all three
+/// to unify into, but `of_manifest`/`of_target` still need a way to pick out the
+/// matching `DepsSet` from a member's manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DepKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+impl DepKind {
+    pub(crate) const ALL: [DepKind; 3] = [DepKind::Normal, DepKind::Dev, DepKind::Build];
+
+    /// The TOML key this kind lives under, e.g. `[dev-dependencies]`.
+    pub(crate) fn key(self) -> &'static str {
+        match self {
+            DepKind::Normal => "dependencies",
+            DepKind::Dev => "dev-dependencies",
+            DepKind::Build => "build-dependencies",
+        }
+    }
+
+    /// This kind's dependency set on a manifest's package-level tables.
+    pub(crate) fn of_manifest(self, manifest: &Manifest) -> &DepsSet {
+        match self {
+            DepKind::Normal => &manifest.dependencies,
+            DepKind::Dev => &manifest.dev_dependencies,
+            DepKind::Build => &manifest.build_dependencies,
+        }
+    }
+
+    /// This kind's dependency set on a `[target.<cfg>]` table.
+    pub(crate) fn of_target(self, target: &Target) -> &DepsSet {
+        match self {
+            DepKind::Normal => &target.dependencies,
+            DepKind::Dev => &target.dev_dependencies,
+            DepKind::Build => &target.build_dependencies,
+        }
+    }
+}
+
+/// Two version requirements that, once intersected, leave no version able to satisfy both.
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum VersionReqConflict {
+    #[error("no version satisfies both `{lower_op} {lower}` and `{upper_op} {upper}`")]
+    BoundsDisjoint {
+        lower: Version,
+        lower_op: &'static str,
+        upper: Version,
+        upper_op: &'static str,
+    },
+    #[error("conflicting exact versions `={left}` and `={right}`")]
+    ExactMismatch { left: Version, right: Version },
+}
 
 pub(crate) trait DependencyExt {
-    fn simplify(&mut self);
+    /// Intersect this dependency's merged version requirement down to its tightest
+    /// satisfiable form, failing if the merge produced an empty range.
+    fn simplify(&mut self) -> Result<(), VersionReqConflict>;
     /// Merge a simple dependency into the current dependency.
     fn merge_simple(&mut self, version: &str);
 
     /// Merge a detailed dependency into the current dependency.
     fn merge_detailed(&mut self, details: Box<DependencyDetail>);
+
+    /// The extra features this dependency enables, empty for a bare version string.
+    fn features(&self) -> &[String];
 }
 
 impl DependencyExt for Dependency {
@@ -30,6 +93,11 @@ impl DependencyExt for Dependency {
                     v.push_str(", ");
                     v.push_str(&version);
                 }
+                // A bare version string requests no extra features, so
+                // intersecting with it drops the running set to empty —
+                // same as any other `merge_detailed` intersection, just
+                // without a `DependencyDetail` to pull a feature list from.
+                detailed.features.clear();
             }
             Dependency::Inherited(_) => {
                 unreachable!("inherited dependencies are not supported")
@@ -48,42 +116,66 @@ impl DependencyExt for Dependency {
                     version.push_str(&detail_version);
                     std::mem::swap(version, detail_version);
                 }
+                // `self` was a bare version, which requests no extra features,
+                // so the intersection is empty regardless of what `details` asked
+                // for — don't let its feature list leak into the workspace entry.
+                details.features.clear();
                 *self = Dependency::Detailed(details);
             }
-            Dependency::Detailed(d) => match (&mut d.version, details.version) {
-                (None, version @ Some(_)) => {
-                    d.version = version;
+            Dependency::Detailed(d) => {
+                match (&mut d.version, details.version) {
+                    (None, version @ Some(_)) => {
+                        d.version = version;
+                    }
+                    (Some(l), Some(r)) => {
+                        l.push_str(", ");
+                        l.push_str(&r);
+                    }
+                    _ => { /* no-op */ }
                 }
-                (Some(l), Some(r)) => {
-                    l.push_str(", ");
-                    l.push_str(&r);
-                }
-                _ => { /* no-op */ }
-            },
+                // Intersect every member's requested features, so the workspace
+                // entry only carries what *every* member agrees on; each member
+                // adds back whatever extra it needs via `rewrite_dependency_table`,
+                // which is exactly what its "extra features" pass is for.
+                d.features
+                    .retain(|feature| details.features.contains(feature));
+                // If any member opted out of default features, nobody should
+                // silently regain them through the shared workspace entry.
+                d.default_features &= details.default_features;
+            }
             Dependency::Inherited(_) => {
                 unreachable!("inherited dependencies are not supported")
             }
         }
     }
 
-    fn simplify(&mut self) {
+    fn features(&self) -> &[String] {
+        match self {
+            Dependency::Simple(_) => &[],
+            Dependency::Detailed(details) => &details.features,
+            Dependency::Inherited(details) => &details.features,
+        }
+    }
+
+    fn simplify(&mut self) -> Result<(), VersionReqConflict> {
         match self {
             Dependency::Simple(version) => {
                 let mut version_req =
                     VersionReq::parse(version).expect("version requirement should be valid");
-                version_req.simplify_version_req();
+                version_req.simplify_version_req()?;
                 *version = version_req.to_string();
             }
             Dependency::Detailed(details) => {
                 if let Some(version) = &mut details.version {
                     let mut version_req =
                         VersionReq::parse(version).expect("version requirement should be valid");
-                    version_req.simplify_version_req();
+                    version_req.simplify_version_req()?;
                     *version = version_req.to_string();
                 }
             }
             Dependency::Inherited(_) => unreachable!("inherited dependencies are not supported"),
         }
+        Ok(())
     }
 }
 
@@ -118,28 +210,317 @@ pub(crate) fn unify_dependencies(
                 }
             }
         }
+        if let Dependency::Detailed(detail) = &mut acc {
+            // `optional` only makes sense per-member; the shared workspace entry
+            // isn't optional to anyone, so don't let one member's choice leak in.
+            detail.optional = false;
+        }
         unified_new_dependencies.insert(name, acc);
     }
     unified_new_dependencies
 }
 
 pub(crate) trait VersionReqExt {
-    /// Simplify a [`VersionReq`].
-    fn simplify_version_req(&mut self);
+    /// Simplify a [`VersionReq`] by intersecting every comparator down to at most
+    /// one tightest lower bound, one tightest upper bound, and an exact pin.
+    ///
+    /// Fails if the intersection is empty, i.e. no version could ever satisfy the
+    /// original requirement.
+    fn simplify_version_req(&mut self) -> Result<(), VersionReqConflict>;
 }
 
 impl VersionReqExt for VersionReq {
-    fn simplify_version_req(&mut self) {
-        self.comparators = std::mem::take(&mut self.comparators)
-            .into_iter()
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect::<_>();
+    fn simplify_version_req(&mut self) -> Result<(), VersionReqConflict> {
+        let mut lower: Option<(Version, bool)> = None;
+        let mut upper: Option<(Version, bool)> = None;
+        let mut exact: Option<Version> = None;
+
+        for comparator in &self.comparators {
+            for bound in expand_comparator(comparator) {
+                match bound {
+                    Bound::Lower { version, inclusive } => {
+                        lower = Some(tighten_lower(lower, (version, inclusive)));
+                    }
+                    Bound::Upper { version, inclusive } => {
+                        upper = Some(tighten_upper(upper, (version, inclusive)));
+                    }
+                    Bound::Exact(version) => {
+                        if let Some(existing) = &exact {
+                            if *existing != version {
+                                return Err(VersionReqConflict::ExactMismatch {
+                                    left: existing.clone(),
+                                    right: version,
+                                });
+                            }
+                        } else {
+                            exact = Some(version);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(exact) = exact {
+            if let Some((lower, lower_inclusive)) = &lower {
+                if !satisfies_lower(&exact, lower, *lower_inclusive) {
+                    return Err(VersionReqConflict::BoundsDisjoint {
+                        lower: lower.clone(),
+                        lower_op: op_str(*lower_inclusive, true),
+                        upper: exact,
+                        upper_op: "=",
+                    });
+                }
+            }
+            if let Some((upper, upper_inclusive)) = &upper {
+                if !satisfies_upper(&exact, upper, *upper_inclusive) {
+                    return Err(VersionReqConflict::BoundsDisjoint {
+                        lower: exact,
+                        lower_op: "=",
+                        upper: upper.clone(),
+                        upper_op: op_str(*upper_inclusive, false),
+                    });
+                }
+            }
+
+            self.comparators = vec![Comparator {
+                op: Op::Exact,
+                major: exact.major,
+                minor: Some(exact.minor),
+                patch: Some(exact.patch),
+                pre: exact.pre,
+            }];
+            return Ok(());
+        }
+
+        self.comparators = match (lower, upper) {
+            (Some((lower, lower_inclusive)), Some((upper, upper_inclusive))) => {
+                let disjoint = match (lower_inclusive, upper_inclusive) {
+                    (true, true) => lower > upper,
+                    _ => lower >= upper,
+                };
+                if disjoint {
+                    return Err(VersionReqConflict::BoundsDisjoint {
+                        lower: lower.clone(),
+                        lower_op: op_str(lower_inclusive, true),
+                        upper: upper.clone(),
+                        upper_op: op_str(upper_inclusive, false),
+                    });
+                }
+                vec![
+                    comparator_from(
+                        &lower,
+                        if lower_inclusive {
+                            Op::GreaterEq
+                        } else {
+                            Op::Greater
+                        },
+                    ),
+                    comparator_from(
+                        &upper,
+                        if upper_inclusive {
+                            Op::LessEq
+                        } else {
+                            Op::Less
+                        },
+                    ),
+                ]
+            }
+            (Some((lower, lower_inclusive)), None) => vec![comparator_from(
+                &lower,
+                if lower_inclusive {
+                    Op::GreaterEq
+                } else {
+                    Op::Greater
+                },
+            )],
+            (None, Some((upper, upper_inclusive))) => vec![comparator_from(
+                &upper,
+                if upper_inclusive {
+                    Op::LessEq
+                } else {
+                    Op::Less
+                },
+            )],
+            // No bounds at all, e.g. a bare `*`: leave the requirement unconstrained.
+            (None, None) => Vec::new(),
+        };
+
+        Ok(())
+    }
+}
+
+fn op_str(inclusive: bool, lower: bool) -> &'static str {
+    match (lower, inclusive) {
+        (true, true) => ">=",
+        (true, false) => ">",
+        (false, true) => "<=",
+        (false, false) => "<",
+    }
+}
+
+fn satisfies_lower(version: &Version, lower: &Version, inclusive: bool) -> bool {
+    if inclusive {
+        version >= lower
+    } else {
+        version > lower
+    }
+}
+
+fn satisfies_upper(version: &Version, upper: &Version, inclusive: bool) -> bool {
+    if inclusive {
+        version <= upper
+    } else {
+        version < upper
+    }
+}
+
+fn tighten_lower(current: Option<(Version, bool)>, candidate: (Version, bool)) -> (Version, bool) {
+    match current {
+        None => candidate,
+        Some(current) => match candidate.0.cmp(&current.0) {
+            Ordering::Greater => candidate,
+            Ordering::Less => current,
+            // Same version: the strict (exclusive) comparator is the tighter one.
+            Ordering::Equal => (current.0, current.1 && candidate.1),
+        },
+    }
+}
+
+fn tighten_upper(current: Option<(Version, bool)>, candidate: (Version, bool)) -> (Version, bool) {
+    match current {
+        None => candidate,
+        Some(current) => match candidate.0.cmp(&current.0) {
+            Ordering::Less => candidate,
+            Ordering::Greater => current,
+            // Same version: the strict (exclusive) comparator is the tighter one.
+            Ordering::Equal => (current.0, current.1 && candidate.1),
+        },
+    }
+}
 
-        // TODO: simplify further
+fn comparator_from(version: &Version, op: Op) -> Comparator {
+    Comparator {
+        op,
+        major: version.major,
+        minor: Some(version.minor),
+        patch: Some(version.patch),
+        pre: version.pre.clone(),
     }
 }
 
+/// One side of an interval produced by expanding a single [`Comparator`].
+enum Bound {
+    Lower { version: Version, inclusive: bool },
+    Upper { version: Version, inclusive: bool },
+    Exact(Version),
+}
+
+/// Expand a comparator into the explicit bound(s) it represents. `Caret`, `Tilde`
+/// and `Wildcard` each stand for a `>=` / `<` pair; the rest map onto themselves.
+fn expand_comparator(comparator: &Comparator) -> Vec<Bound> {
+    let mut version = Version::new(
+        comparator.major,
+        comparator.minor.unwrap_or(0),
+        comparator.patch.unwrap_or(0),
+    );
+    // `Version::new` always starts with an empty `pre`; carry the comparator's
+    // own pre-release tag across so e.g. `=1.2.3-beta.1` doesn't silently
+    // widen into `=1.2.3` once it comes back out through `comparator_from`.
+    version.pre = comparator.pre.clone();
+
+    match comparator.op {
+        Op::Exact => vec![Bound::Exact(version)],
+        Op::Greater => vec![Bound::Lower {
+            version,
+            inclusive: false,
+        }],
+        Op::GreaterEq => vec![Bound::Lower {
+            version,
+            inclusive: true,
+        }],
+        Op::Less => vec![Bound::Upper {
+            version,
+            inclusive: false,
+        }],
+        Op::LessEq => vec![Bound::Upper {
+            version,
+            inclusive: true,
+        }],
+        Op::Caret => expand_caret(comparator, version),
+        Op::Tilde => expand_tilde(comparator, version),
+        Op::Wildcard => expand_wildcard(comparator, version),
+        // `semver::Op` is `#[non_exhaustive]`; treat anything future as unconstrained
+        // rather than panicking on a requirement we don't understand yet.
+        _ => Vec::new(),
+    }
+}
+
+/// `^1.2.3 -> >=1.2.3, <2.0.0` and friends — the first nonzero component may
+/// not change, everything to its right may increase.
+fn expand_caret(comparator: &Comparator, lower: Version) -> Vec<Bound> {
+    let upper = if comparator.major > 0 {
+        Version::new(comparator.major + 1, 0, 0)
+    } else if comparator.minor.is_none() {
+        Version::new(1, 0, 0)
+    } else if lower.minor > 0 {
+        Version::new(0, lower.minor + 1, 0)
+    } else if comparator.patch.is_none() {
+        Version::new(0, 1, 0)
+    } else {
+        Version::new(0, 0, lower.patch + 1)
+    };
+
+    vec![
+        Bound::Lower {
+            version: lower,
+            inclusive: true,
+        },
+        Bound::Upper {
+            version: upper,
+            inclusive: false,
+        },
+    ]
+}
+
+/// `~1.2.3 -> >=1.2.3, <1.3.0`; `~1 -> >=1.0.0, <2.0.0`.
+fn expand_tilde(comparator: &Comparator, lower: Version) -> Vec<Bound> {
+    let upper = if comparator.minor.is_some() {
+        Version::new(lower.major, lower.minor + 1, 0)
+    } else {
+        Version::new(lower.major + 1, 0, 0)
+    };
+
+    vec![
+        Bound::Lower {
+            version: lower,
+            inclusive: true,
+        },
+        Bound::Upper {
+            version: upper,
+            inclusive: false,
+        },
+    ]
+}
+
+/// `1.2.* -> >=1.2.0, <1.3.0`; `1.* -> >=1.0.0, <2.0.0`.
+fn expand_wildcard(comparator: &Comparator, lower: Version) -> Vec<Bound> {
+    let upper = match comparator.minor {
+        Some(minor) => Version::new(lower.major, minor + 1, 0),
+        None => Version::new(lower.major + 1, 0, 0),
+    };
+
+    vec![
+        Bound::Lower {
+            version: lower,
+            inclusive: true,
+        },
+        Bound::Upper {
+            version: upper,
+            inclusive: false,
+        },
+    ]
+}
+
 #[cfg(test)]
 mod tests {
     use cargo_toml::{Dependency, DependencyDetail};
@@ -160,12 +541,16 @@ mod tests {
     fn detailed_simple() {
         let mut original = Dependency::Detailed(Box::new(DependencyDetail {
             version: Some("1.0.0".to_string()),
+            features: vec!["a".to_string()],
             ..Default::default()
         }));
         original.merge_simple("1.9.0");
         assert!(matches!(original, Dependency::Detailed(_)));
         if let Dependency::Detailed(details) = original {
             assert_eq!(details.version, Some("1.0.0, 1.9.0".to_string()));
+            // A bare version requests no extra features, so the running
+            // intersection drops straight to empty.
+            assert!(details.features.is_empty());
         }
     }
 
@@ -174,11 +559,108 @@ mod tests {
         let mut original = Dependency::Simple("1.0.0".to_string());
         original.merge_detailed(Box::new(DependencyDetail {
             version: Some("1.9.0".to_string()),
+            features: vec!["a".to_string()],
             ..Default::default()
         }));
         assert!(matches!(original, Dependency::Detailed(_)));
         if let Dependency::Detailed(details) = original {
             assert_eq!(details.version, Some("1.0.0, 1.9.0".to_string()));
+            // `original` was a bare version, requesting no extra features,
+            // so `details`'s feature list can't leak through the merge.
+            assert!(details.features.is_empty());
+        }
+    }
+
+    #[test]
+    fn detailed_detailed_intersects_features() {
+        let mut original = Dependency::Detailed(Box::new(DependencyDetail {
+            version: Some("1.0.0".to_string()),
+            features: vec!["a".to_string(), "b".to_string()],
+            ..Default::default()
+        }));
+        original.merge_detailed(Box::new(DependencyDetail {
+            version: Some("1.9.0".to_string()),
+            features: vec!["b".to_string(), "c".to_string()],
+            ..Default::default()
+        }));
+        if let Dependency::Detailed(details) = original {
+            assert_eq!(details.features, vec!["b"]);
+        } else {
+            panic!("expected a detailed dependency");
+        }
+    }
+
+    #[test]
+    fn detailed_detailed_disables_default_features_if_any_member_does() {
+        let mut original = Dependency::Detailed(Box::new(DependencyDetail {
+            version: Some("1.0.0".to_string()),
+            default_features: true,
+            ..Default::default()
+        }));
+        original.merge_detailed(Box::new(DependencyDetail {
+            version: Some("1.9.0".to_string()),
+            default_features: false,
+            ..Default::default()
+        }));
+        if let Dependency::Detailed(details) = original {
+            assert!(!details.default_features);
+        } else {
+            panic!("expected a detailed dependency");
+        }
+    }
+
+    mod simplify_version_req {
+        use semver::VersionReq;
+
+        use crate::dependencies::{VersionReqConflict, VersionReqExt};
+
+        #[test]
+        fn overlapping_carets_tighten_to_a_single_range() {
+            let mut req = VersionReq::parse("^1.2.0, ^1.5.0").unwrap();
+            req.simplify_version_req().unwrap();
+            assert_eq!(req.to_string(), ">=1.5.0, <2.0.0");
+        }
+
+        #[test]
+        fn disjoint_majors_are_reported_as_a_conflict() {
+            let mut req = VersionReq::parse("^1, ^2").unwrap();
+            let err = req.simplify_version_req().unwrap_err();
+            assert!(matches!(err, VersionReqConflict::BoundsDisjoint { .. }));
+        }
+
+        #[test]
+        fn matching_exact_pins_collapse_to_one() {
+            let mut req = VersionReq::parse("=1.2.3, =1.2.3").unwrap();
+            req.simplify_version_req().unwrap();
+            assert_eq!(req.to_string(), "=1.2.3");
+        }
+
+        #[test]
+        fn disagreeing_exact_pins_are_reported_as_a_conflict() {
+            let mut req = VersionReq::parse("=1.2.3, =1.2.4").unwrap();
+            let err = req.simplify_version_req().unwrap_err();
+            assert!(matches!(err, VersionReqConflict::ExactMismatch { .. }));
+        }
+
+        #[test]
+        fn redundant_bound_is_dropped() {
+            let mut req = VersionReq::parse(">=1.0.0, >=1.2.0, <2.0.0").unwrap();
+            req.simplify_version_req().unwrap();
+            assert_eq!(req.to_string(), ">=1.2.0, <2.0.0");
+        }
+
+        #[test]
+        fn bare_wildcard_stays_unconstrained() {
+            let mut req = VersionReq::parse("*").unwrap();
+            req.simplify_version_req().unwrap();
+            assert_eq!(req.to_string(), "*");
+        }
+
+        #[test]
+        fn pre_release_exact_pin_keeps_its_pre_release_tag() {
+            let mut req = VersionReq::parse("=1.2.3-beta.1").unwrap();
+            req.simplify_version_req().unwrap();
+            assert_eq!(req.to_string(), "=1.2.3-beta.1");
         }
     }
 }
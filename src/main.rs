@@ -1,13 +1,20 @@
 mod dependencies;
+mod registry;
 
-use std::{collections::BTreeMap, env, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
-use cargo_toml::{Dependency, Manifest};
+use cargo_toml::{Dependency, DepsSet, Manifest};
 use clap::Parser;
-use dependencies::{unify_dependencies, DependencyExt};
+use dependencies::{unify_dependencies, DepKind, DependencyExt};
+use registry::{apply_upgrades, SparseIndexClient, UpgradeMode};
 use serde::Serialize;
 use thiserror::Error;
-use toml_edit::{DocumentMut, Formatted, Item, Value};
+use toml_edit::{Array, DocumentMut, Formatted, InlineTable, Item, TableLike, Value};
 
 fn default_cargo_path() -> PathBuf {
     // NOTE: ngl if it fails here, I don't know what to do
@@ -16,6 +23,69 @@ fn default_cargo_path() -> PathBuf {
     current_dir
 }
 
+/// One line of a unified diff between `before` and `after`.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum DiffLine<'a> {
+    Context(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Line-level diff of `before` against `after`, computed via the textbook LCS
+/// table. `Cargo.toml`s are small enough that the quadratic table is fine, and
+/// it keeps this dependency-free rather than pulling in a diffing crate just
+/// for `--dry-run`.
+fn diff_lines<'a>(before: &'a str, after: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = before.lines().collect();
+    let new: Vec<&str> = after.lines().collect();
+
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(DiffLine::Context(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| DiffLine::Removed(line)));
+    diff.extend(new[j..].iter().map(|line| DiffLine::Added(line)));
+    diff
+}
+
+/// Print a unified diff of `path` to stdout, or nothing if `before == after`.
+fn print_diff(path: &Path, before: &str, after: &str) {
+    if before == after {
+        return;
+    }
+    println!("--- a/{}", path.display());
+    println!("+++ b/{}", path.display());
+    for line in diff_lines(before, after) {
+        match line {
+            DiffLine::Context(line) => println!(" {line}"),
+            DiffLine::Removed(line) => println!("-{line}"),
+            DiffLine::Added(line) => println!("+{line}"),
+        }
+    }
+}
+
 /// Consolidate multiple package dependencies into a single workspace.
 #[derive(Parser)]
 struct App {
@@ -30,6 +100,21 @@ struct App {
     /// Consolidate even if the working directory has staged changes
     #[arg(long, default_value_t = false)]
     allow_staged: bool,
+
+    /// Print a diff of what would change instead of writing anything
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Query the registry and converge each consolidated dependency onto a
+    /// single, up-to-date version. Bare `--upgrade` only picks versions that
+    /// still satisfy what members already required; `--upgrade=incompatible`
+    /// picks the newest version published outright.
+    #[arg(long, num_args = 0..=1, default_missing_value = "compatible", value_enum)]
+    upgrade: Option<UpgradeMode>,
+
+    /// Don't query the registry, even if `--upgrade` was passed.
+    #[arg(long, default_value_t = false)]
+    offline: bool,
 }
 
 #[derive(Error, Debug)]
@@ -44,6 +129,12 @@ enum ConsolidateError {
     IoError(#[from] std::io::Error),
     #[error(transparent)]
     TomlError(#[from] toml_edit::TomlError),
+    #[error("the working directory has unstaged changes; pass --allow-dirty to proceed anyway")]
+    DirtyWorkingTree,
+    #[error(
+        "the working directory has staged but uncommitted changes; pass --allow-staged to proceed anyway"
+    )]
+    StagedChanges,
 }
 
 impl<T> From<ConsolidateError> for Result<T, ConsolidateError> {
@@ -52,8 +143,185 @@ impl<T> From<ConsolidateError> for Result<T, ConsolidateError> {
     }
 }
 
+/// Fold one dependency set (a member's `[dependencies]`, `[dev-dependencies]`, or a
+/// `[target.<cfg>.*-dependencies]` table) into the running unification state for a
+/// single [`DepKind`], skipping anything the workspace already owns or that's already
+/// been consolidated by a previous run.
+fn collect_dependencies(
+    deps: &DepsSet,
+    workspace_dependencies: &DepsSet,
+    member_path: &Path,
+    new_dependencies: &mut BTreeMap<String, Vec<Dependency>>,
+    dependency_origins: &mut BTreeMap<String, Vec<PathBuf>>,
+) {
+    for (name, dependency) in deps {
+        if workspace_dependencies.contains_key(name) {
+            // TODO: check for default-features and friends
+            // maybe we can do that later on too
+            continue;
+        }
+        if matches!(dependency, Dependency::Inherited(_)) {
+            // Already consolidated by a previous run: there is no concrete
+            // version/features left to unify, so there's nothing to do here.
+            continue;
+        }
+        dependency_origins
+            .entry(name.clone())
+            .or_default()
+            .push(member_path.to_path_buf());
+        // Keep track of dependencies with the same name but different version/sources
+        if let Some(dependencies) = new_dependencies.get_mut(name) {
+            dependencies.push(dependency.clone());
+        } else {
+            new_dependencies.insert(name.clone(), vec![dependency.clone()]);
+        }
+    }
+}
+
+/// Drop any dependency whose merged version requirement turned out to be
+/// unsatisfiable, warning the user about where the conflicting declarations live.
+fn simplify_and_report(
+    dependencies: &mut BTreeMap<String, Dependency>,
+    origins: &BTreeMap<String, Vec<PathBuf>>,
+) {
+    dependencies.retain(|name, dependency| match dependency.simplify() {
+        Ok(()) => true,
+        Err(conflict) => {
+            let origins = origins
+                .get(name)
+                .map(|paths| {
+                    paths
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                })
+                .unwrap_or_default();
+            eprintln!("warning: skipping `{name}`: {conflict} (declared in {origins})");
+            false
+        }
+    });
+}
+
+/// Turn every entry in a dependency table that the workspace now owns into an
+/// inheritance marker, mirroring cargo's own `MaybeWorkspace::Workspace` — a bare
+/// string becomes `{ workspace = true }`, a detailed table drops `version` (and
+/// `default-features`, which an inherited dependency can't override) while gaining
+/// `workspace = true` and keeping `optional` local to this member, plus only the
+/// features it needs on top of the ones every member shares, which are the only
+/// features the workspace entry carries.
+fn rewrite_dependency_table(
+    table: &mut dyn TableLike,
+    unified: &BTreeMap<String, Dependency>,
+    typed_deps: &DepsSet,
+) {
+    for (name, value) in table.iter_mut() {
+        let name = name.display_repr().to_string();
+        let Some(dependency) = unified.get(&name) else {
+            continue;
+        };
+        if value.is_str() {
+            let mut inherited = InlineTable::new();
+            inherited.insert("workspace", Value::Boolean(Formatted::new(true)));
+            *value = Item::Value(Value::InlineTable(inherited));
+        } else if let Some(member_table) = value.as_table_like_mut() {
+            member_table.remove("version");
+            member_table.remove("default-features");
+            member_table.remove("features");
+
+            let workspace_features = dependency.features();
+            let extra_features = typed_deps
+                .get(&name)
+                .map(DependencyExt::features)
+                .unwrap_or_default()
+                .iter()
+                .filter(|feature| !workspace_features.contains(feature));
+            let mut array = Array::new();
+            for feature in extra_features {
+                array.push(feature.as_str());
+            }
+            if !array.is_empty() {
+                member_table.insert("features", Item::Value(Value::Array(array)));
+            }
+
+            member_table.insert(
+                "workspace",
+                Item::Value(Value::Boolean(Formatted::new(true))),
+            );
+        } else {
+            unimplemented!("{:?}", value)
+        }
+    }
+}
+
+/// Classify a `git status --porcelain` listing into `(dirty, staged)`: `dirty`
+/// if anything in the working tree differs from the index (including
+/// untracked files), `staged` if anything in the index differs from `HEAD`.
+/// Pulled out of [`App::check_vcs_status`] as a pure function so the porcelain
+/// parsing can be tested against fixture strings without shelling out to git.
+fn parse_porcelain_status(porcelain: &str) -> (bool, bool) {
+    let mut dirty = false;
+    let mut staged = false;
+    for line in porcelain.lines() {
+        let Some(status) = line.as_bytes().get(0..2) else {
+            continue;
+        };
+        let (index, worktree) = (status[0], status[1]);
+        if index == b'?' {
+            // Untracked file: nothing staged, but the tree isn't clean either.
+            dirty = true;
+            continue;
+        }
+        if index != b' ' {
+            staged = true;
+        }
+        if worktree != b' ' {
+            dirty = true;
+        }
+    }
+    (dirty, staged)
+}
+
 impl App {
+    /// Guard against clobbering uncommitted work, mirroring the safety contract cargo's
+    /// own mutating subcommands (`cargo add`, `cargo fix`) apply before editing files in
+    /// place. Shells out to `git status --porcelain` against the directory containing
+    /// the target `Cargo.toml`; if that directory isn't a git repository (or git isn't
+    /// available), there's nothing to guard against and the check is skipped.
+    fn check_vcs_status(&self) -> Result<(), ConsolidateError> {
+        let dir = self.target.parent().unwrap_or_else(|| Path::new("."));
+        let output = match Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["status", "--porcelain"])
+            .output()
+        {
+            Ok(output) => output,
+            // `git` isn't installed: nothing to guard against.
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        if !output.status.success() {
+            return Ok(());
+        }
+
+        let (dirty, staged) = parse_porcelain_status(&String::from_utf8_lossy(&output.stdout));
+
+        if dirty && !self.allow_dirty {
+            return Err(ConsolidateError::DirtyWorkingTree);
+        }
+        if staged && !self.allow_staged {
+            return Err(ConsolidateError::StagedChanges);
+        }
+        Ok(())
+    }
+
     fn consolidate(self, manifest: Manifest) -> Result<(), ConsolidateError> {
+        // A dry run never touches disk, so there's nothing for the VCS guard to protect.
+        if !self.dry_run {
+            self.check_vcs_status()?;
+        }
+
         let Some(workspace) = manifest.workspace else {
             return Err(ConsolidateError::NoWorkspace);
         };
@@ -61,34 +329,57 @@ impl App {
         // Collect all workspace members
         let members = self.read_members(workspace.members)?;
 
-        // Collect all their dependencies that are not in the workspace already
-        // we will make them `workspace = true` later
-        let mut new_dependencies: BTreeMap<_, Vec<Dependency>> = BTreeMap::new();
-        for (_, manifest) in &members {
-            for (name, dependency) in &manifest.dependencies {
-                if workspace.dependencies.contains_key(name) {
-                    // TODO: check for default-features and friends
-                    // maybe we can do that later on too
-                    continue;
-                }
-                // Keep track of dependencies with the same name but different version/sources
-                if let Some(dependencies) = new_dependencies.get_mut(name) {
-                    dependencies.push(dependency.clone());
-                    // TODO: replace dependency version with workspace = true
-                    // do it by going back LATER, this will avoid sourcing conflicts
-                    // because we can just warn the user about them and not do shit
-                } else {
-                    new_dependencies.insert(name.clone(), vec![dependency.clone()]);
+        // Collect all their dependencies that are not in the workspace already, so we
+        // can unify them and make every member inherit them via `workspace = true`.
+        // Real cargo only resolves `workspace = true` against the single
+        // `[workspace.dependencies]` table — there's no per-kind equivalent — so a
+        // crate used as, say, both a normal and a dev-dependency still collects into
+        // one shared entry here.
+        let mut new_dependencies: BTreeMap<String, Vec<Dependency>> = BTreeMap::new();
+        // Track which member manifests contributed each dependency, purely so conflicts
+        // can be reported against something a user can go and look at.
+        let mut dependency_origins: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+
+        for (member_path, manifest) in &members {
+            for kind in DepKind::ALL {
+                collect_dependencies(
+                    kind.of_manifest(manifest),
+                    &workspace.dependencies,
+                    member_path,
+                    &mut new_dependencies,
+                    &mut dependency_origins,
+                );
+            }
+            for target in manifest.target.values() {
+                for kind in DepKind::ALL {
+                    collect_dependencies(
+                        kind.of_target(target),
+                        &workspace.dependencies,
+                        member_path,
+                        &mut new_dependencies,
+                        &mut dependency_origins,
+                    );
                 }
             }
         }
+
         let mut unified_new_dependencies = unify_dependencies(new_dependencies);
-        unified_new_dependencies
-            .iter_mut()
-            .for_each(|(_, dependency)| dependency.simplify());
+        simplify_and_report(&mut unified_new_dependencies, &dependency_origins);
+
+        if let Some(mode) = self.upgrade {
+            if self.offline {
+                eprintln!("warning: --offline set, skipping the registry-backed upgrade pass");
+            } else {
+                apply_upgrades(
+                    &mut unified_new_dependencies,
+                    &SparseIndexClient::crates_io(),
+                    mode,
+                );
+            }
+        }
 
-        let cargo_toml_contents = std::fs::read_to_string(self.target)?;
-        let mut editable_cargo_toml = cargo_toml_contents.parse::<DocumentMut>()?;
+        let workspace_cargo_toml_contents = std::fs::read_to_string(&self.target)?;
+        let mut editable_cargo_toml = workspace_cargo_toml_contents.parse::<DocumentMut>()?;
 
         if let Some(dependencies) = editable_cargo_toml
             .get_mut("workspace")
@@ -102,60 +393,73 @@ impl App {
                 let value = dependency
                     .serialize(toml_edit::ser::ValueSerializer::default())
                     .unwrap();
-                dependencies.insert(&name, toml_edit::Item::Value(value));
+                dependencies.insert(name, Item::Value(value));
             }
         }
 
-        for (member_path, _) in &members {
+        for (member_path, manifest) in &members {
             let member_cargo_toml = std::fs::read_to_string(member_path)?;
             let mut member = member_cargo_toml.parse::<DocumentMut>()?;
-            let dependencies = member
-                .get_mut("dependencies")
-                .expect("dependencies should exist");
-            let dependencies = dependencies
-                .as_table_mut()
-                .expect("dependencies should be in the correct format");
-
-            for (name, value) in dependencies.iter_mut() {
-                if let Some(dep) = unified_new_dependencies.get(name.display_repr().as_ref()) {
-                    if value.is_str() {
-                        match dep {
-                            Dependency::Simple(version) => {
-                                *value =
-                                    Item::Value(Value::String(Formatted::new(version.clone())));
-                            }
-                            Dependency::Detailed(details) => {
-                                let v = (details.version.as_ref()).expect("version should exist");
-                                *value = Item::Value(Value::String(Formatted::new(v.clone())));
-                            }
-                            Dependency::Inherited(_) => { /* no-op */ }
-                        }
-                    } else if value.is_table_like() {
-                        if let Some(version_field) = value.get_mut("version") {
-                            match dep {
-                                Dependency::Simple(version) => {
-                                    let value = Value::String(Formatted::new(version.clone()));
-                                    *version_field = Item::Value(value)
-                                }
-                                Dependency::Detailed(details) => {
-                                    let v =
-                                        (details.version.as_ref()).expect("version should exist");
-                                    let value = Value::String(Formatted::new(v.clone()));
-                                    *version_field = Item::Value(value)
-                                }
-                                Dependency::Inherited(_) => { /* no-op */ }
-                            }
+
+            for kind in DepKind::ALL {
+                if let Some(table) = member
+                    .get_mut(kind.key())
+                    .and_then(|item| item.as_table_like_mut())
+                {
+                    rewrite_dependency_table(
+                        table,
+                        &unified_new_dependencies,
+                        kind.of_manifest(manifest),
+                    );
+                }
+            }
+
+            if let Some(targets) = member
+                .get_mut("target")
+                .and_then(|item| item.as_table_like_mut())
+            {
+                for (cfg, target_item) in targets.iter_mut() {
+                    let Some(typed_target) = manifest.target.get(cfg.get()) else {
+                        continue;
+                    };
+                    let Some(target_table) = target_item.as_table_like_mut() else {
+                        continue;
+                    };
+                    for kind in DepKind::ALL {
+                        if let Some(table) = target_table
+                            .get_mut(kind.key())
+                            .and_then(|item| item.as_table_like_mut())
+                        {
+                            rewrite_dependency_table(
+                                table,
+                                &unified_new_dependencies,
+                                kind.of_target(typed_target),
+                            );
                         }
-                    } else {
-                        unimplemented!("{:?}", value)
                     }
                 }
             }
 
-            std::fs::write(member_path, member.to_string())?;
+            if self.dry_run {
+                print_diff(member_path, &member_cargo_toml, &member.to_string());
+            } else {
+                std::fs::write(member_path, member.to_string())?;
+            }
         }
 
-        Ok(std::fs::write("test", editable_cargo_toml.to_string())?)
+        if self.dry_run {
+            print_diff(
+                &self.target,
+                &workspace_cargo_toml_contents,
+                &editable_cargo_toml.to_string(),
+            );
+            return Ok(());
+        }
+
+        Ok(std::fs::write(
+            &self.target,
+            editable_cargo_toml.to_string(),
+        )?)
     }
 
     fn read_members(
@@ -199,3 +503,71 @@ fn main() -> Result<(), anyhow::Error> {
     let cargo_contents = Manifest::from_path(&app.target)?;
     Ok(app.consolidate(cargo_contents)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_lines, parse_porcelain_status, DiffLine};
+
+    #[test]
+    fn clean_tree_is_neither_dirty_nor_staged() {
+        assert_eq!(parse_porcelain_status(""), (false, false));
+    }
+
+    #[test]
+    fn untracked_file_is_dirty_but_not_staged() {
+        assert_eq!(parse_porcelain_status("?? new.txt\n"), (true, false));
+    }
+
+    #[test]
+    fn unstaged_modification_is_dirty_but_not_staged() {
+        assert_eq!(parse_porcelain_status(" M foo\n"), (true, false));
+    }
+
+    #[test]
+    fn staged_modification_is_staged_but_not_dirty() {
+        assert_eq!(parse_porcelain_status("M  foo\n"), (false, true));
+    }
+
+    #[test]
+    fn partially_staged_modification_is_both_dirty_and_staged() {
+        assert_eq!(parse_porcelain_status("MM foo\n"), (true, true));
+    }
+
+    #[test]
+    fn identical_text_has_only_context_lines() {
+        let diff = diff_lines("a\nb\n", "a\nb\n");
+        assert_eq!(diff, vec![DiffLine::Context("a"), DiffLine::Context("b")]);
+    }
+
+    #[test]
+    fn inserted_line_shows_up_as_added() {
+        let diff = diff_lines("a\nc\n", "a\nb\nc\n");
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Context("a"),
+                DiffLine::Added("b"),
+                DiffLine::Context("c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_line_shows_up_as_removed() {
+        let diff = diff_lines("a\nb\nc\n", "a\nc\n");
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Context("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Context("c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn replaced_line_shows_up_as_removed_then_added() {
+        let diff = diff_lines("a\n", "b\n");
+        assert_eq!(diff, vec![DiffLine::Removed("a"), DiffLine::Added("b")]);
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+
+use cargo_toml::Dependency;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// How aggressively `--upgrade` is allowed to move a consolidated dependency.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UpgradeMode {
+    /// Pick the newest published version that still satisfies the
+    /// requirement unification already produced.
+    Compatible,
+    /// Pick the newest published version outright, discarding the existing
+    /// requirement rather than being bound by it.
+    Incompatible,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum RegistryError {
+    #[error("failed to reach the registry: {0}")]
+    Request(#[from] Box<ureq::Error>),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse registry index entry: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// One line of a sparse-index response: https://doc.rust-lang.org/cargo/reference/registry-index.html
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: Version,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// Looks up the versions of a crate that a registry has published, abstracted
+/// behind a trait so the upgrade pass can be exercised in tests without
+/// making a real network call.
+pub(crate) trait RegistryClient {
+    /// Every non-yanked version of `name` the registry has published.
+    fn published_versions(&self, name: &str) -> Result<Vec<Version>, RegistryError>;
+}
+
+/// Talks to a registry's sparse index — the protocol `cargo` itself has used
+/// against crates.io since 1.68 — fetching the newline-delimited JSON list of
+/// every version ever published for a crate.
+pub(crate) struct SparseIndexClient {
+    /// Base URL of the sparse index, e.g. `https://index.crates.io`.
+    base_url: String,
+}
+
+impl SparseIndexClient {
+    pub(crate) fn crates_io() -> Self {
+        Self {
+            base_url: "https://index.crates.io".to_string(),
+        }
+    }
+}
+
+impl RegistryClient for SparseIndexClient {
+    fn published_versions(&self, name: &str) -> Result<Vec<Version>, RegistryError> {
+        let url = format!("{}/{}", self.base_url, sparse_index_path(name));
+        let body = ureq::get(&url).call().map_err(Box::new)?.into_string()?;
+
+        let mut versions = Vec::new();
+        for line in body.lines().filter(|line| !line.is_empty()) {
+            let entry: IndexEntry = serde_json::from_str(line)?;
+            if !entry.yanked {
+                versions.push(entry.vers);
+            }
+        }
+        Ok(versions)
+    }
+}
+
+/// The sparse index shards crates into directories by name length, mirroring
+/// `cargo`'s own layout rules: https://doc.rust-lang.org/cargo/reference/registry-index.html#index-files
+fn sparse_index_path(name: &str) -> String {
+    let lower = name.to_lowercase();
+    match lower.len() {
+        1 => format!("1/{lower}"),
+        2 => format!("2/{lower}"),
+        3 => format!("3/{}/{lower}", &lower[..1]),
+        _ => format!("{}/{}/{lower}", &lower[..2], &lower[2..4]),
+    }
+}
+
+/// The version requirement a registry upgrade should respect, or `None` if
+/// `dependency` isn't a plain crates.io dependency — a git/path source has no
+/// registry versions to pick from, and an alternate `registry = "..."` isn't
+/// one [`SparseIndexClient`] knows how to query.
+fn registry_requirement(dependency: &Dependency) -> Option<VersionReq> {
+    let version = match dependency {
+        Dependency::Simple(version) => Some(version),
+        Dependency::Detailed(details)
+            if details.git.is_none() && details.path.is_none() && details.registry.is_none() =>
+        {
+            details.version.as_ref()
+        }
+        _ => None,
+    }?;
+    VersionReq::parse(version).ok()
+}
+
+/// Pick the upgrade target for one consolidated dependency out of the
+/// versions a registry has published.
+fn pick_upgrade_version(
+    requirement: &VersionReq,
+    published: &[Version],
+    mode: UpgradeMode,
+) -> Option<Version> {
+    published
+        .iter()
+        .filter(|version| mode == UpgradeMode::Incompatible || requirement.matches(version))
+        .max()
+        .cloned()
+}
+
+/// Set a dependency's version requirement to an exact pin on `version`,
+/// converging it onto the single concrete release the registry lookup chose.
+fn pin_version(dependency: &mut Dependency, version: &Version) {
+    let pinned = format!("={version}");
+    match dependency {
+        Dependency::Simple(existing) => *existing = pinned,
+        Dependency::Detailed(details) => details.version = Some(pinned),
+        Dependency::Inherited(_) => unreachable!("inherited dependencies are not supported"),
+    }
+}
+
+/// Query `registry` for each consolidated dependency and pin it to a single
+/// up-to-date version, converging the workspace instead of merely preserving
+/// whatever range its members already agreed on. Dependencies the registry
+/// can't resolve a satisfying version for are left untouched and reported;
+/// dependencies that aren't plain registry dependencies are left untouched
+/// without a warning.
+pub(crate) fn apply_upgrades(
+    dependencies: &mut BTreeMap<String, Dependency>,
+    registry: &dyn RegistryClient,
+    mode: UpgradeMode,
+) {
+    for (name, dependency) in dependencies.iter_mut() {
+        let Some(requirement) = registry_requirement(dependency) else {
+            continue;
+        };
+        let published = match registry.published_versions(name) {
+            Ok(versions) => versions,
+            Err(error) => {
+                eprintln!("warning: skipping upgrade for `{name}`: {error}");
+                continue;
+            }
+        };
+        match pick_upgrade_version(&requirement, &published, mode) {
+            Some(target) => pin_version(dependency, &target),
+            None => eprintln!(
+                "warning: no published version of `{name}` satisfies `{requirement}`, leaving it as-is"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use cargo_toml::Dependency;
+    use semver::{Version, VersionReq};
+
+    use super::{apply_upgrades, pick_upgrade_version, RegistryClient, RegistryError, UpgradeMode};
+
+    /// A registry double keyed by crate name, so the upgrade pass can be
+    /// tested without a real network call.
+    struct FakeRegistryClient(BTreeMap<&'static str, Vec<Version>>);
+
+    impl RegistryClient for FakeRegistryClient {
+        fn published_versions(&self, name: &str) -> Result<Vec<Version>, RegistryError> {
+            Ok(self.0.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    fn version(v: &str) -> Version {
+        Version::parse(v).unwrap()
+    }
+
+    #[test]
+    fn compatible_mode_picks_newest_satisfying_version() {
+        let requirement = VersionReq::parse("^1.0").unwrap();
+        let published = vec![version("1.2.0"), version("1.5.0"), version("2.0.0")];
+        let picked = pick_upgrade_version(&requirement, &published, UpgradeMode::Compatible);
+        assert_eq!(picked, Some(version("1.5.0")));
+    }
+
+    #[test]
+    fn incompatible_mode_ignores_the_requirement() {
+        let requirement = VersionReq::parse("^1.0").unwrap();
+        let published = vec![version("1.5.0"), version("2.3.0")];
+        let picked = pick_upgrade_version(&requirement, &published, UpgradeMode::Incompatible);
+        assert_eq!(picked, Some(version("2.3.0")));
+    }
+
+    #[test]
+    fn no_satisfying_version_picks_nothing() {
+        let requirement = VersionReq::parse("^1.0").unwrap();
+        let published = vec![version("2.0.0")];
+        let picked = pick_upgrade_version(&requirement, &published, UpgradeMode::Compatible);
+        assert_eq!(picked, None);
+    }
+
+    #[test]
+    fn apply_upgrades_pins_the_selected_version() {
+        let registry = FakeRegistryClient(BTreeMap::from([(
+            "serde",
+            vec![version("1.0.150"), version("1.0.200")],
+        )]));
+        let mut dependencies =
+            BTreeMap::from([("serde".to_string(), Dependency::Simple("1.0".to_string()))]);
+
+        apply_upgrades(&mut dependencies, &registry, UpgradeMode::Compatible);
+
+        assert!(matches!(
+            &dependencies["serde"],
+            Dependency::Simple(version) if version == "=1.0.200"
+        ));
+    }
+
+    #[test]
+    fn git_dependencies_are_left_alone() {
+        let registry = FakeRegistryClient(BTreeMap::new());
+        let mut dependencies = BTreeMap::from([(
+            "local-fork".to_string(),
+            Dependency::Detailed(Box::new(cargo_toml::DependencyDetail {
+                git: Some("https://example.com/local-fork".to_string()),
+                ..Default::default()
+            })),
+        )]);
+
+        apply_upgrades(&mut dependencies, &registry, UpgradeMode::Compatible);
+
+        assert!(matches!(
+            &dependencies["local-fork"],
+            Dependency::Detailed(details) if details.version.is_none()
+        ));
+    }
+
+    #[test]
+    fn alternate_registry_dependencies_are_left_alone() {
+        let registry =
+            FakeRegistryClient(BTreeMap::from([("internal-crate", vec![version("9.9.9")])]));
+        let mut dependencies = BTreeMap::from([(
+            "internal-crate".to_string(),
+            Dependency::Detailed(Box::new(cargo_toml::DependencyDetail {
+                version: Some("1.0".to_string()),
+                registry: Some("my-company-registry".to_string()),
+                ..Default::default()
+            })),
+        )]);
+
+        apply_upgrades(&mut dependencies, &registry, UpgradeMode::Compatible);
+
+        assert!(matches!(
+            &dependencies["internal-crate"],
+            Dependency::Detailed(details) if details.version.as_deref() == Some("1.0")
+        ));
+    }
+}